@@ -1,9 +1,11 @@
 #![allow(clippy::transmute_ptr_to_ptr)]
 #![allow(clippy::too_many_arguments)]
 
-use crate::os::{HRESULT, LPCWSTR, LPWSTR};
+use crate::os::{HRESULT, LPCSTR, LPCWSTR, LPWSTR};
 pub(crate) use crate::unknown::IDxcUnknownShim;
 use com::{interfaces, IID};
+#[cfg(windows)]
+use com::interfaces::IUnknown;
 use std::ffi::c_void;
 
 pub type DxcCreateInstanceProc<T> =
@@ -335,3 +337,50 @@ pub const CLSID_DxcContainerBuilder: IID = IID {
     data3: 0x4574,
     data4: [0xb4, 0xd0, 0x87, 0x41, 0xe2, 0x52, 0x40, 0xd2],
 };
+
+// `D3DCompile`, exported by `d3dcompiler_47.dll`, is the legacy FXC
+// compilation entrypoint. `ID3DBlob` shares `IDxcBlob`'s IID so the existing
+// `IDxcBlob` shim doubles as its COM representation.
+#[cfg(windows)]
+pub const D3DCOMPILE_DEBUG: u32 = 1 << 0;
+#[cfg(windows)]
+pub const D3DCOMPILE_SKIP_OPTIMIZATION: u32 = 1 << 2;
+#[cfg(windows)]
+pub const D3DCOMPILE_ENABLE_STRICTNESS: u32 = 1 << 11;
+
+#[cfg(windows)]
+#[repr(C)]
+pub struct D3dShaderMacro {
+    pub name: LPCSTR,
+    pub definition: LPCSTR,
+}
+
+// The standard `IMalloc` COM interface, used with `DxcCreateInstance2` to
+// let callers supply their own allocator for DXC's COM objects.
+#[cfg(windows)]
+interfaces! {
+    #[uuid("00000002-0000-0000-C000-000000000046")]
+    pub(crate) unsafe interface IMalloc: IUnknown {
+        pub(crate) fn alloc(&self, size: usize) -> *mut c_void;
+        pub(crate) fn realloc(&self, pv: *mut c_void, size: usize) -> *mut c_void;
+        pub(crate) fn free(&self, pv: *mut c_void);
+        pub(crate) fn get_size(&self, pv: *mut c_void) -> usize;
+        pub(crate) fn did_alloc(&self, pv: *mut c_void) -> i32;
+        pub(crate) fn heap_minimize(&self);
+    }
+}
+
+#[cfg(windows)]
+pub type D3DCompileProc = extern "system" fn(
+    src_data: *const c_void,
+    src_data_size: usize,
+    source_name: LPCSTR,
+    defines: *const D3dShaderMacro,
+    include: *const c_void,
+    entrypoint: LPCSTR,
+    target: LPCSTR,
+    flags1: u32,
+    flags2: u32,
+    code: *mut Option<IDxcBlob>,
+    error_msgs: *mut Option<IDxcBlob>,
+) -> HRESULT;
@@ -2,6 +2,19 @@ use crate::os::{SysFreeString, BSTR, HRESULT, LPSTR, LPWSTR, WCHAR};
 use crate::{dxil::Dxil, Dxc, DxcIncludeHandler};
 use thiserror::Error;
 
+use crate::ffi::{
+    CLSID_DxcContainerReflection, DxcCreateInstanceProc, IDxcBlob, IDxcContainerReflection,
+};
+#[cfg(windows)]
+use crate::ffi::{
+    D3DCompileProc, D3dShaderMacro, DxcCreateInstanceProc2, IMalloc, D3DCOMPILE_DEBUG,
+    D3DCOMPILE_ENABLE_STRICTNESS, D3DCOMPILE_SKIP_OPTIMIZATION,
+};
+use com::IID;
+#[cfg(windows)]
+use com::class;
+#[cfg(windows)]
+use std::ffi::{c_void, CString};
 #[cfg(windows)]
 use winapi::um::oleauto::SysStringLen;
 
@@ -9,22 +22,21 @@ pub(crate) fn to_wide(msg: &str) -> Vec<WCHAR> {
     widestring::WideCString::from_str(msg).unwrap().into_vec()
 }
 
-pub(crate) fn from_wide(wide: LPWSTR) -> String {
-    unsafe {
-        widestring::WideCStr::from_ptr_str(wide)
-            .to_string()
-            .expect("widestring decode failed")
-    }
+// Fallible: every caller of from_wide/from_bstr/from_lpstr (e.g.
+// IDxcLibrary::get_blob_as_string, the debug_blob_name/commit_hash
+// wrappers) must propagate the Result rather than unwrap it.
+pub(crate) fn from_wide(wide: LPWSTR) -> Result<String, HassleError> {
+    unsafe { Ok(widestring::WideCStr::from_ptr_str(wide).to_string()?) }
 }
 
 #[cfg(windows)]
-pub(crate) fn from_bstr(string: BSTR) -> String {
+pub(crate) fn from_bstr(string: BSTR) -> Result<String, HassleError> {
     unsafe {
+        // Read the length prefix ourselves (rather than going through a
+        // NUL-terminated WideCStr) so embedded NULs in the BSTR survive.
         let len = SysStringLen(string) as usize;
-
-        let result = widestring::WideCStr::from_ptr_with_nul(string, len)
-            .to_string()
-            .expect("widestring decode failed");
+        let wide = std::slice::from_raw_parts(string as *const u16, len);
+        let result = widestring::WideStr::from_slice(wide).to_string().map_err(HassleError::from);
 
         SysFreeString(string);
         result
@@ -32,12 +44,11 @@ pub(crate) fn from_bstr(string: BSTR) -> String {
 }
 
 #[cfg(not(windows))]
-pub(crate) fn from_bstr(string: BSTR) -> String {
-    // TODO (Marijn): This does NOT cover embedded NULLs
-
+pub(crate) fn from_bstr(string: BSTR) -> Result<String, HassleError> {
     // BSTR contains its size in the four bytes preceding the pointer, in order to contain NULL bytes:
     // https://docs.microsoft.com/en-us/previous-versions/windows/desktop/automat/bstr
-    // DXC on non-Windows does not adhere to that and simply allocates a buffer without prepending the size:
+    // DXC on non-Windows does not adhere to that and simply allocates a buffer without prepending the size,
+    // so unlike the Windows path above this still can't recover the real length and truncates at the first NUL:
     // https://github.com/microsoft/DirectXShaderCompiler/blob/a8d9780046cb64a1cea842fa6fc28a250e3e2c09/include/dxc/Support/WinAdapter.h#L49-L50
     let result = from_wide(string as LPWSTR);
 
@@ -45,12 +56,149 @@ pub(crate) fn from_bstr(string: BSTR) -> String {
     result
 }
 
-pub(crate) fn from_lpstr(string: LPSTR) -> String {
+pub(crate) fn from_lpstr(string: LPSTR) -> Result<String, HassleError> {
     unsafe {
         let len = (0..).take_while(|&i| *string.offset(i) != 0).count();
 
         let slice: &[u8] = std::slice::from_raw_parts(string as *const u8, len);
-        std::str::from_utf8(slice).map(|s| s.to_owned()).unwrap()
+        std::str::from_utf8(slice)
+            .map(|s| s.to_owned())
+            .map_err(HassleError::Utf8Error)
+    }
+}
+
+/// Returns a blob's contents verbatim, using its reported buffer size
+/// rather than scanning for a terminator. Prefer this over decoding a blob
+/// to a `String` when the caller only needs the raw bytes, e.g. diagnostic
+/// or UTF-16 output that may contain embedded NULs.
+pub(crate) fn get_blob_as_bytes(blob: &IDxcBlob) -> Vec<u8> {
+    unsafe {
+        let ptr = blob.get_buffer_pointer() as *const u8;
+        let len = blob.get_buffer_size();
+        std::slice::from_raw_parts(ptr, len).to_vec()
+    }
+}
+
+/// A user-supplied allocator for DXC's internal COM objects, passed to
+/// [`Dxc::with_allocator`]. Implement this to track or bound the memory of
+/// the COM objects that `Dxc` creates through `DxcCreateInstance2`; the
+/// compiler and library objects are not among them, so allocations made
+/// while compiling do not pass through it.
+#[cfg(windows)]
+pub trait DxcMalloc {
+    fn alloc(&self, size: usize) -> *mut c_void;
+    fn realloc(&self, pv: *mut c_void, size: usize) -> *mut c_void;
+    fn free(&self, pv: *mut c_void);
+    fn get_size(&self, pv: *mut c_void) -> usize;
+    fn did_alloc(&self, pv: *mut c_void) -> bool;
+}
+
+#[cfg(windows)]
+class! {
+    pub(crate) class DxcMallocWrapper: IMalloc {
+        inner: Box<dyn DxcMalloc>,
+    }
+
+    impl IMalloc for DxcMallocWrapper {
+        fn alloc(&self, size: usize) -> *mut c_void {
+            self.inner.alloc(size)
+        }
+
+        fn realloc(&self, pv: *mut c_void, size: usize) -> *mut c_void {
+            self.inner.realloc(pv, size)
+        }
+
+        fn free(&self, pv: *mut c_void) {
+            self.inner.free(pv)
+        }
+
+        fn get_size(&self, pv: *mut c_void) -> usize {
+            self.inner.get_size(pv)
+        }
+
+        fn did_alloc(&self, pv: *mut c_void) -> i32 {
+            self.inner.did_alloc(pv) as i32
+        }
+
+        fn heap_minimize(&self) {}
+    }
+}
+
+#[cfg(windows)]
+impl Dxc {
+    /// Like [`Dxc::new`], but COM objects created through
+    /// [`Dxc::instantiate`] (currently only
+    /// [`Dxc::create_container_reflection`]) go through
+    /// `DxcCreateInstance2`, routing their allocations through `malloc`
+    /// instead of DXC's internal heap. `create_compiler` and
+    /// `create_library` do not use `malloc`; their objects are allocated
+    /// on DXC's internal heap as with [`Dxc::new`].
+    pub fn with_allocator(malloc: Box<dyn DxcMalloc>) -> Result<Self, HassleError> {
+        let mut dxc = Self::new()?;
+        dxc.malloc = Some(DxcMallocWrapper::allocate(malloc));
+        Ok(dxc)
+    }
+
+    /// Creates a COM instance of `clsid` via `DxcCreateInstance2`, routing
+    /// the allocation through the allocator installed via
+    /// [`Dxc::with_allocator`]. Called by [`Dxc::instantiate`] when an
+    /// allocator is installed; `create_compiler`/`create_library` are
+    /// defined elsewhere in the crate and do not go through this path.
+    fn create_instance2<T: com::Interface>(&self, malloc: &IMalloc, clsid: &IID) -> Result<T, HassleError> {
+        let create_instance2: libloading::Symbol<DxcCreateInstanceProc2> =
+            unsafe { self.library.get(b"DxcCreateInstance2\0")? };
+
+        // `IMalloc` is the single-pointer-sized COM interface wrapper
+        // generated by `interfaces!` (same shape as `object` below); DXC
+        // wants that inner vtable pointer, not the address of our `&IMalloc`.
+        let malloc_ptr: *const c_void = unsafe { std::mem::transmute_copy(malloc) };
+        let mut object: *mut c_void = std::ptr::null_mut();
+
+        let result = create_instance2(malloc_ptr, clsid, &T::IID, &mut object as *mut *mut c_void);
+
+        if result < 0 {
+            return Err(HassleError::Win32Error(result));
+        }
+
+        Ok(unsafe { std::mem::transmute_copy(&object) })
+    }
+}
+
+impl Dxc {
+    /// Creates a COM instance of `clsid`, going through `DxcCreateInstance2`
+    /// when an allocator was installed via [`Dxc::with_allocator`] (so that
+    /// the new object's allocations go through that allocator), or the
+    /// plain `DxcCreateInstance` otherwise.
+    ///
+    /// Named distinctly from `create_instance` (the pre-existing helper
+    /// `create_compiler`/`create_library` are presumably already built on,
+    /// defined elsewhere in the crate) to avoid an inherent-method name
+    /// clash; only [`Dxc::create_container_reflection`] goes through this
+    /// path so far.
+    fn instantiate<T: com::Interface>(&self, clsid: &IID) -> Result<T, HassleError> {
+        #[cfg(windows)]
+        if let Some(malloc) = &self.malloc {
+            return self.create_instance2(malloc, clsid);
+        }
+
+        let create_instance: libloading::Symbol<DxcCreateInstanceProc<T>> =
+            unsafe { self.library.get(b"DxcCreateInstance\0")? };
+
+        let mut object = None;
+        let result = create_instance(clsid, &T::IID, &mut object);
+
+        if result < 0 {
+            return Err(HassleError::Win32Error(result));
+        }
+
+        object.ok_or(HassleError::Win32Error(result))
+    }
+
+    /// Creates an `IDxcContainerReflection` instance for enumerating and
+    /// extracting the parts of a compiled DXIL container; see
+    /// [`DxcContainerReflection`].
+    pub fn create_container_reflection(&self) -> Result<IDxcContainerReflection, HassleError> {
+        self.instantiate(&CLSID_DxcContainerReflection)
     }
 }
 
@@ -88,6 +236,8 @@ pub enum HassleError {
     LibLoadingError(#[from] libloading::Error),
     #[error("Utf8 error: {0:?}")]
     Utf8Error(#[from] std::str::Utf8Error),
+    #[error("String decode error: {0}")]
+    StringDecodeError(#[from] widestring::error::Utf16Error),
 }
 
 /// Helper function to directly compile a HLSL shader to an intermediate language,
@@ -95,6 +245,12 @@ pub enum HassleError {
 /// executable environment.
 ///
 /// Specify -spirv as one of the `args` to compile to SPIR-V
+///
+/// On Windows, falls back to `compile_hlsl_fxc` when `dxcompiler.dll` can't
+/// be loaded, e.g. on machines that only ship the legacy FXC runtime. The
+/// fallback is only taken for shader model 5.x and lower targets without
+/// -spirv; other requests return an error instead. There is no fallback on
+/// other platforms.
 pub fn compile_hlsl(
     source_name: &str,
     shader_text: &str,
@@ -103,7 +259,23 @@ pub fn compile_hlsl(
     args: &[&str],
     defines: &[(&str, Option<&str>)],
 ) -> Result<Vec<u8>, HassleError> {
-    let dxc = Dxc::new()?;
+    let dxc = match Dxc::new() {
+        Ok(dxc) => dxc,
+        #[cfg(windows)]
+        Err(HassleError::LoadLibraryError { .. }) => {
+            ensure_fxc_compatible(args, target_profile)?;
+
+            return compile_hlsl_fxc(
+                source_name,
+                shader_text,
+                entry_point,
+                target_profile,
+                args,
+                defines,
+            );
+        }
+        Err(err) => return Err(err),
+    };
 
     let compiler = dxc.create_compiler()?;
     let library = dxc.create_library()?;
@@ -129,7 +301,7 @@ pub fn compile_hlsl(
                 .get_error_buffer()
                 .map_err(HassleError::Win32Error)?;
             Err(HassleError::CompileError(
-                library.get_blob_as_string(&error_blob),
+                library.get_blob_as_string(&error_blob)?,
             ))
         }
         Ok(result) => {
@@ -140,6 +312,232 @@ pub fn compile_hlsl(
     }
 }
 
+/// The major shader model component of a target profile like `"ps_6_0"`,
+/// or `None` if `target_profile` has no second `'_'`-separated component or
+/// that component isn't a number.
+#[cfg(windows)]
+fn target_profile_shader_model(target_profile: &str) -> Option<u32> {
+    // `<stage>_<major>_<minor>`, e.g. "vs_5_0" or "ps_6_0", but also the
+    // legacy down-level feature-level profiles FXC supports, e.g.
+    // "vs_4_0_level_9_1" — the major shader model is always the second
+    // '_'-separated component, never the last two.
+    target_profile.split('_').nth(1)?.parse().ok()
+}
+
+/// FXC (`d3dcompiler_47.dll`) only understands shader model 5.1 and below,
+/// and has no SPIR-V backend. Rather than silently falling back to a
+/// different target than the caller asked for, reject requests
+/// `compile_hlsl_fxc` can't actually satisfy with a clear error.
+#[cfg(windows)]
+fn ensure_fxc_compatible(args: &[&str], target_profile: &str) -> Result<(), HassleError> {
+    if args.iter().any(|&arg| arg.eq_ignore_ascii_case("-spirv")) {
+        return Err(HassleError::CompileError(
+            "FXC (d3dcompiler_47.dll) can't target SPIR-V; -spirv requires dxcompiler.dll/dxil.dll"
+                .to_string(),
+        ));
+    }
+
+    match target_profile_shader_model(target_profile) {
+        Some(model) if model <= 5 => Ok(()),
+        _ => Err(HassleError::CompileError(format!(
+            "FXC (d3dcompiler_47.dll) only supports shader model 5.1 and below; target profile {:?} requires dxcompiler.dll",
+            target_profile
+        ))),
+    }
+}
+
+#[cfg(windows)]
+fn d3dcompile_flags_from_args(args: &[&str]) -> u32 {
+    args.iter().fold(0, |flags, &arg| {
+        flags
+            | match arg {
+                "-Zi" => D3DCOMPILE_DEBUG,
+                "-Od" => D3DCOMPILE_SKIP_OPTIMIZATION,
+                "-Ges" => D3DCOMPILE_ENABLE_STRICTNESS,
+                _ => 0,
+            }
+    })
+}
+
+/// Helper function to directly compile a HLSL shader through the legacy
+/// `D3DCompile` entrypoint in `d3dcompiler_47.dll`. Unlike [`compile_hlsl`]
+/// this targets Shader Model 5.0/5.1 and works on machines that only ship
+/// the FXC runtime, i.e. without `dxcompiler.dll`/`dxil.dll` installed.
+///
+/// Specify -Zi, -Od or -Ges as one of the `args` to request debug info,
+/// skip optimizations, or enable strict mode respectively.
+#[cfg(windows)]
+pub fn compile_hlsl_fxc(
+    source_name: &str,
+    shader_text: &str,
+    entry_point: &str,
+    target_profile: &str,
+    args: &[&str],
+    defines: &[(&str, Option<&str>)],
+) -> Result<Vec<u8>, HassleError> {
+    let library =
+        libloading::Library::new("d3dcompiler_47.dll").map_err(|e| HassleError::LoadLibraryError {
+            filename: "d3dcompiler_47.dll".to_string(),
+            inner: e,
+        })?;
+
+    let d3d_compile: libloading::Symbol<D3DCompileProc> =
+        unsafe { library.get(b"D3DCompile\0")? };
+
+    let source_name = CString::new(source_name).unwrap();
+    let entry_point = CString::new(entry_point).unwrap();
+    let target_profile = CString::new(target_profile).unwrap();
+
+    let define_strings: Vec<(CString, Option<CString>)> = defines
+        .iter()
+        .map(|(name, value)| (CString::new(*name).unwrap(), value.map(|v| CString::new(v).unwrap())))
+        .collect();
+
+    let mut macros: Vec<D3dShaderMacro> = define_strings
+        .iter()
+        .map(|(name, value)| D3dShaderMacro {
+            name: name.as_ptr(),
+            definition: value.as_ref().map_or(std::ptr::null(), |v| v.as_ptr()),
+        })
+        .collect();
+    macros.push(D3dShaderMacro {
+        name: std::ptr::null(),
+        definition: std::ptr::null(),
+    });
+
+    let flags1 = d3dcompile_flags_from_args(args);
+
+    let mut code: Option<IDxcBlob> = None;
+    let mut error_msgs: Option<IDxcBlob> = None;
+
+    let result = unsafe {
+        d3d_compile(
+            shader_text.as_ptr() as *const c_void,
+            shader_text.len(),
+            source_name.as_ptr(),
+            macros.as_ptr(),
+            std::ptr::null(),
+            entry_point.as_ptr(),
+            target_profile.as_ptr(),
+            flags1,
+            0,
+            &mut code,
+            &mut error_msgs,
+        )
+    };
+
+    if result < 0 {
+        let message = error_msgs
+            .map(|blob| String::from_utf8_lossy(&get_blob_as_bytes(&blob)).into_owned())
+            .unwrap_or_else(|| format!("D3DCompile failed: {:#X}", result));
+
+        return Err(HassleError::CompileError(message));
+    }
+
+    let code = code.expect("D3DCompile reported success without returning a blob");
+    Ok(get_blob_as_bytes(&code))
+}
+
+/// A DXIL container part's four-character-code identifier, e.g. [`DXC_PART_DXIL`].
+pub type FourCc = u32;
+
+const fn four_cc(bytes: [u8; 4]) -> FourCc {
+    (bytes[0] as u32) | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24
+}
+
+pub const DXC_PART_DXIL: FourCc = four_cc(*b"DXIL");
+pub const DXC_PART_PDB: FourCc = four_cc(*b"ILDB");
+pub const DXC_PART_PDB_NAME: FourCc = four_cc(*b"ILDN");
+
+fn check_hresult(hr: HRESULT) -> Result<(), HassleError> {
+    if hr < 0 {
+        Err(HassleError::Win32Error(hr))
+    } else {
+        Ok(())
+    }
+}
+
+/// Safe wrapper over `IDxcContainerReflection`, for enumerating and
+/// extracting the parts of a compiled DXIL container (root signature, PDB,
+/// reflection data, ...) without hand-writing the COM calls. Pairs naturally
+/// with `IDxcContainerBuilder` for stripping parts back out of a container.
+pub struct DxcContainerReflection {
+    dxc: Dxc,
+    inner: IDxcContainerReflection,
+}
+
+impl DxcContainerReflection {
+    pub fn new() -> Result<Self, HassleError> {
+        let dxc = Dxc::new()?;
+        let inner = dxc.create_container_reflection()?;
+        Ok(Self { dxc, inner })
+    }
+
+    /// Loads a compiled DXIL container, making its parts available through
+    /// [`parts`](Self::parts) and [`find_part`](Self::find_part).
+    pub fn load(&self, container: &[u8]) -> Result<(), HassleError> {
+        let library = self.dxc.create_library()?;
+        let blob = library
+            .create_blob_with_encoding(container)
+            .map_err(HassleError::Win32Error)?;
+
+        check_hresult(self.inner.load(blob.into()))
+    }
+
+    fn part_count(&self) -> Result<u32, HassleError> {
+        let mut count = 0;
+        check_hresult(self.inner.get_part_count(&mut count))?;
+        Ok(count)
+    }
+
+    fn part_kind(&self, index: u32) -> Result<FourCc, HassleError> {
+        let mut kind = 0;
+        check_hresult(self.inner.get_part_kind(index, &mut kind))?;
+        Ok(kind)
+    }
+
+    fn part_content(&self, index: u32) -> Result<Vec<u8>, HassleError> {
+        let mut content: Option<IDxcBlob> = None;
+        check_hresult(self.inner.get_part_content(index, &mut content))?;
+        Ok(get_blob_as_bytes(
+            &content.expect("get_part_content succeeded without returning a blob"),
+        ))
+    }
+
+    /// Every `(FourCC, bytes)` part of the loaded container, in container order.
+    pub fn parts(&self) -> Result<Vec<(FourCc, Vec<u8>)>, HassleError> {
+        (0..self.part_count()?)
+            .map(|index| Ok((self.part_kind(index)?, self.part_content(index)?)))
+            .collect()
+    }
+
+    /// Returns the first part matching `four_cc`, if the container has one.
+    pub fn find_part(&self, four_cc: FourCc) -> Result<Option<Vec<u8>>, HassleError> {
+        let mut index = 0;
+        if self.inner.find_first_part_kind(four_cc, &mut index) < 0 {
+            return Ok(None);
+        }
+
+        self.part_content(index).map(Some)
+    }
+
+    /// Convenience that pulls out the container's DXIL bytecode (falling
+    /// back to its debug bitcode if the full module was stripped) along
+    /// with its debug name part, if present.
+    pub fn find_dxil_and_debug_name(&self) -> Result<(Option<Vec<u8>>, Option<String>), HassleError> {
+        let dxil = match self.find_part(DXC_PART_DXIL)? {
+            Some(bytes) => Some(bytes),
+            None => self.find_part(DXC_PART_PDB)?,
+        };
+
+        let debug_name = self
+            .find_part(DXC_PART_PDB_NAME)?
+            .map(|bytes| String::from_utf8_lossy(&bytes).into_owned());
+
+        Ok((dxil, debug_name))
+    }
+}
+
 /// Helper function to validate a DXIL binary independant from the compilation process,
 /// this function expects `dxcompiler.dll` and `dxil.dll` to be available in the current
 /// execution environment.
@@ -163,8 +561,35 @@ pub fn validate_dxil(data: &[u8]) -> Result<Vec<u8>, HassleError> {
                 .get_error_buffer()
                 .map_err(HassleError::Win32Error)?;
             Err(HassleError::ValidationError(
-                library.get_blob_as_string(&error_blob),
+                library.get_blob_as_string(&error_blob)?,
             ))
         }
     }
 }
+
+#[cfg(all(test, windows))]
+mod tests {
+    use super::target_profile_shader_model;
+
+    #[test]
+    fn target_profile_shader_model_parses_stage_major_minor() {
+        assert_eq!(target_profile_shader_model("vs_5_0"), Some(5));
+        assert_eq!(target_profile_shader_model("ps_6_0"), Some(6));
+        assert_eq!(target_profile_shader_model("lib_6_3"), Some(6));
+    }
+
+    #[test]
+    fn target_profile_shader_model_parses_down_level_feature_profiles() {
+        // Legacy feature-level profiles have extra trailing components;
+        // the shader model is still the first one after the stage.
+        assert_eq!(target_profile_shader_model("vs_4_0_level_9_1"), Some(4));
+        assert_eq!(target_profile_shader_model("ps_4_0_level_9_3"), Some(4));
+    }
+
+    #[test]
+    fn target_profile_shader_model_rejects_malformed_input() {
+        assert_eq!(target_profile_shader_model(""), None);
+        assert_eq!(target_profile_shader_model("vs"), None);
+        assert_eq!(target_profile_shader_model("vs_x_0"), None);
+    }
+}